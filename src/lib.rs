@@ -18,12 +18,15 @@ use winit_types::error::Error;
 use winit_types::platform::{OsError, XError, XNotSupported};
 use x11_dl::error::OpenError;
 
-use std::ffi::CStr;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
 use std::mem::MaybeUninit;
 use std::ops::{Deref, DerefMut};
 use std::os::raw;
+use std::os::unix::io::RawFd;
 use std::ptr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once, Weak};
 
 lazy_static! {
     pub static ref XEXT: Result<x11_dl::dpms::Xext, OpenError> = x11_dl::dpms::Xext::open();
@@ -55,8 +58,153 @@ lazy_static! {
     pub static ref XLIB: Result<x11_dl::xlib::Xlib, OpenError> = x11_dl::xlib::Xlib::open();
     pub static ref XLIB_XCB: Result<x11_dl::xlib_xcb::Xlib_xcb, OpenError> =
         x11_dl::xlib_xcb::Xlib_xcb::open();
-    pub static ref X11_DISPLAY: Mutex<Result<Arc<Display>, Error>> =
-        { Mutex::new(Display::new().map(Arc::new)) };
+    pub static ref X11_DISPLAY: Mutex<Result<Arc<Display>, Error>> = {
+        let result = Display::new().map(Arc::new);
+        if let Ok(ref display) = result {
+            DISPLAY_REGISTRY
+                .lock()
+                .insert(DisplayKey(display.display), Arc::downgrade(display));
+        }
+        Mutex::new(result)
+    };
+    static ref PREV_ERROR_HANDLER: Mutex<Option<XErrorHandlerFn>> = Mutex::new(None);
+    static ref ERROR_HOOKS: Mutex<Vec<ErrorHook>> = Mutex::new(Vec::new());
+    static ref DISPLAY_REGISTRY: Mutex<HashMap<DisplayKey, Weak<Display>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Key into [`DISPLAY_REGISTRY`], the raw display pointer a `Display` wraps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct DisplayKey(*mut x11_dl::xlib::Display);
+
+// Only ever touched behind `DISPLAY_REGISTRY`'s mutex.
+unsafe impl Send for DisplayKey {}
+
+/// The raw `XErrorHandler` function pointer type accepted by `XSetErrorHandler`.
+type XErrorHandlerFn =
+    unsafe extern "C" fn(*mut x11_dl::xlib::Display, *mut x11_dl::xlib::XErrorEvent) -> raw::c_int;
+
+static ERROR_HANDLER_INIT: Once = Once::new();
+
+/// Installs `x_error_callback` as the process-wide `XErrorHandler` and records
+/// whatever handler was previously installed, exactly once.
+///
+/// `XSetErrorHandler` is process-global, not per-display, so calling it again
+/// on every `Display::new_named` (e.g. once `open_named` opens a second
+/// display) would hand us back our own `x_error_callback` as the "previous"
+/// handler, making it call itself forever on the next unclaimed error.
+fn ensure_error_handler_installed(xlib: &x11_dl::xlib::Xlib) {
+    ERROR_HANDLER_INIT.call_once(|| {
+        let prev_handler = unsafe { (xlib.XSetErrorHandler)(Some(x_error_callback)) };
+        *PREV_ERROR_HANDLER.lock() = prev_handler;
+    });
+}
+
+/// Identifies a hook registered via [`insert_error_hook`], used to remove it again.
+pub type HandlerId = usize;
+
+/// Shape constants from `<X11/cursorfont.h>`, used as the core-font fallback
+/// when a themed cursor can't be found via Xcursor. Values are fixed by the
+/// X11 core font, not re-exported by `x11_dl`, so they're spelled out here.
+#[allow(dead_code)]
+mod core_font_cursor {
+    use std::os::raw::c_uint;
+
+    pub const X_CURSOR: c_uint = 0;
+    pub const BOTTOM_LEFT_CORNER: c_uint = 12;
+    pub const BOTTOM_RIGHT_CORNER: c_uint = 14;
+    pub const CROSSHAIR: c_uint = 34;
+    pub const FLEUR: c_uint = 52;
+    pub const HAND1: c_uint = 58;
+    pub const HAND2: c_uint = 60;
+    pub const LEFT_PTR: c_uint = 68;
+    pub const SB_H_DOUBLE_ARROW: c_uint = 108;
+    pub const SB_V_DOUBLE_ARROW: c_uint = 116;
+    pub const TOP_LEFT_CORNER: c_uint = 134;
+    pub const TOP_RIGHT_CORNER: c_uint = 136;
+    pub const WATCH: c_uint = 150;
+    pub const XTERM: c_uint = 152;
+}
+
+/// Maps a themed cursor name (following the CSS/Xcursor naming convention,
+/// e.g. `"pointer"`, `"wait"`, `"ew-resize"`) to the closest core-font shape,
+/// for use when the current Xcursor theme has no cursor under that name.
+/// Unrecognized names fall back to the plain arrow, same as an unthemed X
+/// session would show.
+fn core_font_shape(name: &str) -> raw::c_uint {
+    use core_font_cursor::*;
+
+    match name {
+        "crosshair" => CROSSHAIR,
+        "move" | "all-scroll" => FLEUR,
+        "grab" => HAND1,
+        "grabbing" | "pointer" => HAND2,
+        "text" | "vertical-text" => XTERM,
+        "wait" | "progress" => WATCH,
+        "col-resize" | "e-resize" | "w-resize" | "ew-resize" => SB_H_DOUBLE_ARROW,
+        "row-resize" | "n-resize" | "s-resize" | "ns-resize" => SB_V_DOUBLE_ARROW,
+        "ne-resize" | "nesw-resize" => TOP_RIGHT_CORNER,
+        "nw-resize" | "nwse-resize" => TOP_LEFT_CORNER,
+        "se-resize" => BOTTOM_RIGHT_CORNER,
+        "sw-resize" => BOTTOM_LEFT_CORNER,
+        "not-allowed" | "no-drop" => X_CURSOR,
+        _ => LEFT_PTR,
+    }
+}
+
+/// Key into `Display`'s cursor cache: the themed cursor name that was requested
+/// via [`Display::load_cursor`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CursorKey(String);
+
+static NEXT_HANDLER_ID: AtomicUsize = AtomicUsize::new(0);
+
+struct ErrorHook {
+    id: HandlerId,
+    display: *mut x11_dl::xlib::Display,
+    callback:
+        Arc<dyn Fn(*mut x11_dl::xlib::Display, *mut x11_dl::xlib::XErrorEvent) -> bool + Send + Sync>,
+}
+
+// `ErrorHook` is only ever touched behind `ERROR_HOOKS`'s mutex, so the raw
+// `display` pointer it carries is never accessed concurrently.
+unsafe impl Send for ErrorHook {}
+
+/// A guard returned by [`insert_error_hook`] that removes the hook on drop.
+#[derive(Debug)]
+pub struct ErrorHookGuard {
+    id: HandlerId,
+}
+
+impl Drop for ErrorHookGuard {
+    #[inline]
+    fn drop(&mut self) {
+        ERROR_HOOKS.lock().retain(|hook| hook.id != self.id);
+    }
+}
+
+/// Registers a hook that is consulted by this crate's global `XErrorHandler` for
+/// errors belonging to `display`.
+///
+/// Hooks run in registration order before any error is recorded by this crate.
+/// Return `true` from `callback` to claim the error (stopping other hooks and the
+/// previous handler from seeing it); return `false` to let it fall through. If no
+/// hook claims an error, it is forwarded to whatever `XErrorHandler` was installed
+/// before this crate's, so libraries sharing the process (GTK, SDL, ...) keep
+/// seeing their own errors.
+///
+/// The returned [`ErrorHookGuard`] removes the hook when dropped.
+pub fn insert_error_hook<F>(display: *mut x11_dl::xlib::Display, callback: F) -> ErrorHookGuard
+where
+    F: Fn(*mut x11_dl::xlib::Display, *mut x11_dl::xlib::XErrorEvent) -> bool + Send + Sync + 'static,
+{
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+    ERROR_HOOKS.lock().push(ErrorHook {
+        id,
+        display,
+        callback: Arc::new(callback),
+    });
+    ErrorHookGuard { id }
 }
 
 #[macro_export]
@@ -73,7 +221,9 @@ macro_rules! lsyms {
 #[derive(Debug)]
 pub struct Display {
     pub display: *mut x11_dl::xlib::Display,
+    pub x11_fd: RawFd,
     pub latest_error: Mutex<Option<Error>>,
+    cursor_cache: Mutex<HashMap<CursorKey, x11_dl::xlib::Cursor>>,
     owned: bool,
 }
 
@@ -83,13 +233,18 @@ unsafe impl Sync for Display {}
 impl Display {
     #[inline]
     fn new() -> Result<Display, Error> {
+        Self::new_named(None)
+    }
+
+    fn new_named(name: Option<&CStr>) -> Result<Display, Error> {
         let xlib = lsyms!(XLIB);
         unsafe { (xlib.XInitThreads)() };
-        unsafe { (xlib.XSetErrorHandler)(Some(x_error_callback)) };
+        ensure_error_handler_installed(xlib);
 
         // calling XOpenDisplay
         let display = unsafe {
-            let display = (xlib.XOpenDisplay)(ptr::null());
+            let name_ptr = name.map_or(ptr::null(), |name| name.as_ptr());
+            let display = (xlib.XOpenDisplay)(name_ptr);
             if display.is_null() {
                 return Err(make_oserror!(OsError::XNotSupported(
                     XNotSupported::XOpenDisplayFailed
@@ -98,29 +253,57 @@ impl Display {
             display
         };
 
+        let x11_fd = unsafe { (xlib.XConnectionNumber)(display) };
+
         Ok(Display {
             display,
+            x11_fd,
             latest_error: Mutex::new(None),
+            cursor_cache: Mutex::new(HashMap::new()),
             owned: true,
         })
     }
 
+    /// Opens the X server named by `name` (`$DISPLAY` syntax), or the default
+    /// display when `name` is `None`, and registers it for [`Display::from_raw`].
+    pub fn open_named(name: Option<&CStr>) -> Result<Arc<Display>, Error> {
+        let display = Arc::new(Self::new_named(name)?);
+        DISPLAY_REGISTRY
+            .lock()
+            .insert(DisplayKey(display.display), Arc::downgrade(&display));
+        Ok(display)
+    }
+
     #[inline]
     pub fn from_raw(display: *mut raw::c_void) -> Arc<Display> {
-        if let Ok(ref x11_display) = *X11_DISPLAY.lock() {
-            if x11_display.display == display as *mut _ {
-                return Arc::clone(x11_display);
-            }
+        let key = DisplayKey(display as *mut _);
+        if let Some(registered) = DISPLAY_REGISTRY.lock().get(&key).and_then(Weak::upgrade) {
+            return registered;
         }
 
-        warn!("X11 display not X11_DISPLAY's display, users of this display will not know errors.");
+        warn!("X11 display not previously opened by this crate, users of this display will not know errors.");
+        let xlib = lsyms!(XLIB);
+        let display = display as *mut _;
+        let x11_fd = unsafe { (xlib.XConnectionNumber)(display) };
         Arc::new(Display {
-            display: display as *mut _,
+            display,
+            x11_fd,
             latest_error: Mutex::new(None),
+            cursor_cache: Mutex::new(HashMap::new()),
             owned: false,
         })
     }
 
+    /// Returns the raw file descriptor of the underlying X11 connection.
+    ///
+    /// This can be registered with an external poll/epoll-based event loop
+    /// (e.g. `mio` or `calloop`) so callers can block until the connection
+    /// has data pending instead of busy-polling `XPending`.
+    #[inline]
+    pub fn fd(&self) -> RawFd {
+        self.x11_fd
+    }
+
     /// Checks whether an error has been triggered by the previous function calls.
     #[inline]
     pub fn check_errors(&self) -> Result<(), Error> {
@@ -137,13 +320,167 @@ impl Display {
     pub fn ignore_error(&self) {
         *self.latest_error.lock() = None;
     }
+
+    /// Runs `f`, then returns the X protocol error (if any) caused by requests
+    /// issued while `f` was running, bracketed by request serial.
+    pub fn catch_errors<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> T,
+    {
+        let xlib = lsyms!(XLIB);
+        let captured: Arc<Mutex<HashMap<raw::c_ulong, Error>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let captured_hook = Arc::clone(&captured);
+
+        let start_serial = unsafe { (xlib.XNextRequest)(self.display) };
+
+        let guard = insert_error_hook(self.display, move |display_ptr, event| {
+            let xlib = lsyms!(XLIB);
+            let error = unsafe { describe_x_error(xlib, display_ptr, &*event) };
+            let serial = unsafe { (*event).serial };
+            captured_hook.lock().insert(serial, error);
+            false
+        });
+
+        let result = f();
+
+        unsafe { (xlib.XSync)(self.display, 0) };
+        let end_serial = unsafe { (xlib.XNextRequest)(self.display) };
+
+        drop(guard);
+
+        // An `x_error_callback` running on another thread may still hold a clone
+        // of the hook, so drain the buffer instead of unwrapping the `Arc`.
+        let captured = std::mem::take(&mut *captured.lock());
+
+        for (serial, error) in captured {
+            if serial_in_range(serial, start_serial, end_serial) {
+                return Err(error);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Loads a named themed cursor, falling back to a core font cursor, caching
+    /// the result.
+    pub fn load_cursor(&self, name: &str) -> Result<x11_dl::xlib::Cursor, Error> {
+        let key = CursorKey(name.to_owned());
+
+        if let Some(&cursor) = self.cursor_cache.lock().get(&key) {
+            return Ok(cursor);
+        }
+
+        let xcursor = lsyms!(XCURSOR);
+        let xlib = lsyms!(XLIB);
+
+        let c_name = CString::new(name).map_err(|_| {
+            make_oserror!(OsError::XError(XError {
+                description: format!("cursor name {:?} contains an interior NUL byte", name),
+                error_code: 0,
+                request_code: 0,
+                minor_code: 0,
+            }))
+        })?;
+        let cursor = unsafe { (xcursor.XcursorLibraryLoadCursor)(self.display, c_name.as_ptr()) };
+        let cursor = if cursor == 0 {
+            unsafe { (xlib.XCreateFontCursor)(self.display, core_font_shape(name)) }
+        } else {
+            cursor
+        };
+
+        self.cursor_cache.lock().insert(key, cursor);
+
+        Ok(cursor)
+    }
+
+    /// Returns the `xcb_connection_t` underlying this display via `XGetXCBConnection`.
+    pub fn xcb_connection(&self) -> Result<*mut raw::c_void, Error> {
+        let xlib_xcb = load_xlib_xcb()?;
+        let connection = unsafe { (xlib_xcb.XGetXCBConnection)(self.display) };
+        Ok(connection as *mut raw::c_void)
+    }
+
+    /// Hands ownership of the X11 event queue to XCB. Must be called before any
+    /// Xlib event pumping (`XNextEvent`, `XPending`, ...).
+    pub fn set_event_queue_owner_xcb(&self) -> Result<(), Error> {
+        let xlib_xcb = load_xlib_xcb()?;
+        unsafe {
+            (xlib_xcb.XSetEventQueueOwner)(
+                self.display,
+                x11_dl::xlib_xcb::XEventQueueOwner::XCBOwnsEventQueue,
+            );
+        }
+        Ok(())
+    }
+
+    /// Hands ownership of the X11 event queue back to Xlib.
+    pub fn set_event_queue_owner_xlib(&self) -> Result<(), Error> {
+        let xlib_xcb = load_xlib_xcb()?;
+        unsafe {
+            (xlib_xcb.XSetEventQueueOwner)(
+                self.display,
+                x11_dl::xlib_xcb::XEventQueueOwner::XlibOwnsEventQueue,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Whether `serial` lies in the half-open range `[start, end)`, accounting for
+/// `unsigned long` wraparound.
+#[inline]
+fn serial_in_range(serial: raw::c_ulong, start: raw::c_ulong, end: raw::c_ulong) -> bool {
+    serial.wrapping_sub(start) < end.wrapping_sub(start)
+}
+
+/// Returns the loaded `Xlib_xcb` bindings, or an `Error` if `libX11-xcb` failed to load.
+fn load_xlib_xcb() -> Result<&'static x11_dl::xlib_xcb::Xlib_xcb, Error> {
+    XLIB_XCB.as_ref().map_err(|open_err| {
+        make_oserror!(OsError::XError(XError {
+            description: format!("failed to load libX11-xcb: {:?}", open_err),
+            error_code: 0,
+            request_code: 0,
+            minor_code: 0,
+        }))
+    })
+}
+
+/// Builds the crate's `Error` for an `XErrorEvent`, fetching its human-readable
+/// description via `XGetErrorText`.
+unsafe fn describe_x_error(
+    xlib: &x11_dl::xlib::Xlib,
+    display_ptr: *mut x11_dl::xlib::Display,
+    event: &x11_dl::xlib::XErrorEvent,
+) -> Error {
+    // `assume_init` is safe here because the array consists of `MaybeUninit` values,
+    // which do not require initialization.
+    let mut buf: [MaybeUninit<raw::c_char>; 1024] = MaybeUninit::uninit().assume_init();
+    (xlib.XGetErrorText)(
+        display_ptr,
+        event.error_code as raw::c_int,
+        buf.as_mut_ptr() as *mut raw::c_char,
+        buf.len() as raw::c_int,
+    );
+    let description = CStr::from_ptr(buf.as_ptr() as *const raw::c_char).to_string_lossy();
+
+    make_oserror!(OsError::XError(XError {
+        description: description.into_owned(),
+        error_code: event.error_code,
+        request_code: event.request_code,
+        minor_code: event.minor_code,
+    }))
 }
 
 impl Drop for Display {
     #[inline]
     fn drop(&mut self) {
         if self.owned {
+            DISPLAY_REGISTRY.lock().remove(&DisplayKey(self.display));
             let xlib = lsyms!(XLIB);
+            for &cursor in self.cursor_cache.lock().values() {
+                unsafe { (xlib.XFreeCursor)(self.display, cursor) };
+            }
             unsafe { (xlib.XCloseDisplay)(self.display) };
         }
     }
@@ -153,31 +490,54 @@ unsafe extern "C" fn x_error_callback(
     display_ptr: *mut x11_dl::xlib::Display,
     event: *mut x11_dl::xlib::XErrorEvent,
 ) -> raw::c_int {
-    let xlib = lsyms!(XLIB);
-    let display = X11_DISPLAY.lock();
-    if let Ok(ref display) = *display {
-        // `assume_init` is safe here because the array consists of `MaybeUninit` values,
-        // which do not require initialization.
-        let mut buf: [MaybeUninit<raw::c_char>; 1024] = MaybeUninit::uninit().assume_init();
-        (xlib.XGetErrorText)(
-            display_ptr,
-            (*event).error_code as raw::c_int,
-            buf.as_mut_ptr() as *mut raw::c_char,
-            buf.len() as raw::c_int,
-        );
-        let description = CStr::from_ptr(buf.as_ptr() as *const raw::c_char).to_string_lossy();
-
-        let error = make_oserror!(OsError::XError(XError {
-            description: description.into_owned(),
-            error_code: (*event).error_code,
-            request_code: (*event).request_code,
-            minor_code: (*event).minor_code,
-        }));
-
-        error!("X11 error: {:#?}", error);
-
-        *display.latest_error.lock() = Some(error);
+    // Snapshot the hooks (cheap `Arc` clones) and drop the lock before invoking any
+    // of them: a hook that registers another hook, or whose own `ErrorHookGuard` is
+    // dropped from within itself, would otherwise deadlock on `ERROR_HOOKS`.
+    let hooks: Vec<_> = ERROR_HOOKS
+        .lock()
+        .iter()
+        .filter(|hook| hook.display == display_ptr)
+        .map(|hook| Arc::clone(&hook.callback))
+        .collect();
+
+    let mut claimed = false;
+    for callback in hooks {
+        if callback(display_ptr, event) {
+            claimed = true;
+            break;
+        }
     }
+
+    if !claimed {
+        let xlib = lsyms!(XLIB);
+
+        // The error belongs to whichever `Display` actually owns `display_ptr`,
+        // not necessarily the default `X11_DISPLAY` — fall back to that only when
+        // nothing this crate opened is registered under `display_ptr`.
+        let display = DISPLAY_REGISTRY
+            .lock()
+            .get(&DisplayKey(display_ptr))
+            .and_then(Weak::upgrade)
+            .or_else(|| match *X11_DISPLAY.lock() {
+                Ok(ref display) => Some(Arc::clone(display)),
+                Err(_) => None,
+            });
+
+        if let Some(display) = display {
+            let error = describe_x_error(xlib, display_ptr, &*event);
+
+            error!("X11 error: {:#?}", error);
+
+            *display.latest_error.lock() = Some(error);
+        }
+    }
+
+    if !claimed {
+        if let Some(prev_handler) = *PREV_ERROR_HANDLER.lock() {
+            return prev_handler(display_ptr, event);
+        }
+    }
+
     // Fun fact: this return value is completely ignored.
     0
 }